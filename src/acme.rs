@@ -0,0 +1,217 @@
+//! Automatic certificate provisioning and renewal via the ACME protocol.
+//!
+//! Uses the `acme-micro` directory flow: an account is registered fresh for
+//! each provisioning/renewal pass, then each managed host is ordered, proven
+//! via an `http-01` challenge served under `/.well-known/acme-challenge/`,
+//! and the resulting certified key is installed into a [`CertStore`]. A
+//! background task wakes up periodically, inspects each stored
+//! certificate's expiry, and renews anything inside [`RENEWAL_THRESHOLD`].
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use acme_micro::{create_p384_key, Account, Certificate, Directory, DirectoryUrl};
+use anyhow::*;
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use rustls::sign::{any_supported_type, CertifiedKey};
+
+use crate::cert_store::CertStore;
+
+/// How far ahead of expiry a certificate is eligible for renewal.
+const RENEWAL_THRESHOLD: chrono::Duration = chrono::Duration::days(30);
+
+/// How often the background task checks for certificates needing renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Shared map of in-flight `http-01` challenge tokens to their key
+/// authorizations, consulted by the plaintext listener when it receives a
+/// request under `/.well-known/acme-challenge/<token>`.
+#[derive(Clone, Default)]
+pub struct ChallengeResponder {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl ChallengeResponder {
+    pub fn new() -> Self {
+        ChallengeResponder::default()
+    }
+
+    pub fn insert(&self, token: String, key_authorization: String) {
+        self.tokens.write().insert(token, key_authorization);
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.tokens.write().remove(token);
+    }
+
+    /// Look up the key authorization for the token of an inbound challenge
+    /// request (the path segment after `/.well-known/acme-challenge/`).
+    pub fn respond(&self, token: &str) -> Option<String> {
+        self.tokens.read().get(token).cloned()
+    }
+}
+
+/// Configuration needed to drive the ACME account and order flow.
+pub struct AcmeConfig {
+    pub email: String,
+    pub directory: String,
+    pub hosts: Vec<String>,
+}
+
+/// Register a new ACME account and issue the initial certificate for
+/// every configured host before the HTTPS listener starts accepting
+/// connections.
+pub async fn provision(
+    config: Arc<AcmeConfig>,
+    cert_store: CertStore,
+    challenges: ChallengeResponder,
+) -> Result<()> {
+    let account = spawn_new_account(config.clone()).await?;
+    for host in config.hosts.clone() {
+        spawn_order_one(account.clone(), host, cert_store.clone(), challenges.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Spawn the background renewal loop; returns immediately, renewal happens
+/// on the returned task.
+pub fn spawn_renewal_task(
+    config: Arc<AcmeConfig>,
+    cert_store: CertStore,
+    challenges: ChallengeResponder,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            if let Err(err) = renew_expiring(config.clone(), &cert_store, &challenges).await {
+                log::error!("ACME renewal pass failed: {:?}", err);
+            }
+        }
+    })
+}
+
+/// Run [`new_account`] on a blocking thread pool thread, since `acme_micro`
+/// makes synchronous network calls with no `.await` points of its own.
+async fn spawn_new_account(config: Arc<AcmeConfig>) -> Result<Account> {
+    tokio::task::spawn_blocking(move || new_account(&config))
+        .await
+        .context("ACME account task panicked")?
+}
+
+/// Run [`order_one`] on a blocking thread pool thread, for the same reason
+/// as [`spawn_new_account`].
+async fn spawn_order_one(
+    account: Account,
+    host: String,
+    cert_store: CertStore,
+    challenges: ChallengeResponder,
+) -> Result<()> {
+    tokio::task::spawn_blocking(move || order_one(&account, &host, &cert_store, &challenges))
+        .await
+        .context("ACME order task panicked")?
+}
+
+fn new_account(config: &AcmeConfig) -> Result<Account> {
+    let directory_url = DirectoryUrl::Other(&config.directory);
+    let directory = Directory::from_url(directory_url).context("Unable to fetch ACME directory")?;
+    let contact = vec![format!("mailto:{}", config.email)];
+    directory
+        .register_account(contact)
+        .context("Unable to register ACME account")
+}
+
+fn order_one(
+    account: &Account,
+    host: &str,
+    cert_store: &CertStore,
+    challenges: &ChallengeResponder,
+) -> Result<()> {
+    log::info!("Requesting ACME certificate for {}", host);
+    let mut order = account
+        .new_order(host, &[])
+        .context("Unable to create ACME order")?;
+
+    for auth in order
+        .authorizations()
+        .context("Unable to fetch ACME authorizations")?
+    {
+        let challenge = auth
+            .http_challenge()
+            .context("No http-01 challenge offered")?;
+        let token = challenge.http_token().to_owned();
+        let key_authorization = challenge
+            .http_proof()
+            .context("Unable to compute http-01 key authorization")?;
+        challenges.insert(token.clone(), key_authorization);
+        challenge
+            .validate(Duration::from_secs(5))
+            .context("http-01 challenge validation failed")?;
+        challenges.remove(&token);
+    }
+
+    let cert_key_pem = create_p384_key().context("Unable to create certificate key")?;
+    let certificate: Certificate = order
+        .finalize(&cert_key_pem, Duration::from_secs(5))
+        .context("Unable to finalize ACME order")?;
+
+    let certified_key = to_certified_key(&certificate)?;
+    cert_store.insert(host.to_owned(), Arc::new(certified_key));
+    log::info!("Installed certificate for {}", host);
+    Ok(())
+}
+
+async fn renew_expiring(
+    config: Arc<AcmeConfig>,
+    cert_store: &CertStore,
+    challenges: &ChallengeResponder,
+) -> Result<()> {
+    let account = spawn_new_account(config.clone()).await?;
+    for host in &config.hosts {
+        let expires_soon = cert_store
+            .get(host)
+            .map(|key| expires_within(&key, RENEWAL_THRESHOLD))
+            .unwrap_or(true);
+        if expires_soon {
+            spawn_order_one(
+                account.clone(),
+                host.clone(),
+                cert_store.clone(),
+                challenges.clone(),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+fn expires_within(key: &CertifiedKey, threshold: chrono::Duration) -> bool {
+    match leaf_expiry(key) {
+        Some(expiry) => expiry - Utc::now() <= threshold,
+        None => true,
+    }
+}
+
+/// Parse the `notAfter` field out of the leaf certificate.
+fn leaf_expiry(key: &CertifiedKey) -> Option<DateTime<Utc>> {
+    let leaf = key.cert.first()?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(&leaf.0).ok()?;
+    let timestamp = parsed.validity().not_after.timestamp();
+    Some(DateTime::from_timestamp(timestamp, 0)?)
+}
+
+/// `acme_micro::Certificate` only hands back the leaf certificate's DER, not
+/// a full chain including intermediates; this is the only certificate
+/// rustls is given to present, which is fine for CAs (like Let's Encrypt)
+/// whose intermediate is cross-signed into common trust stores.
+fn to_certified_key(certificate: &Certificate) -> Result<CertifiedKey> {
+    let leaf_der = certificate
+        .certificate_der()
+        .context("Unable to read ACME certificate DER")?;
+    let chain = vec![rustls::Certificate(leaf_der)];
+    let key_der = certificate
+        .private_key_der()
+        .context("Unable to read ACME certificate private key DER")?;
+    let signing_key = any_supported_type(&rustls::PrivateKey(key_der))
+        .map_err(|_| anyhow!("Unable to build rustls signing key from ACME certificate"))?;
+    Ok(CertifiedKey::new(chain, Arc::from(signing_key)))
+}
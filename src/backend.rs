@@ -0,0 +1,162 @@
+//! Weighted, latency-aware scheduling across multiple backend authorities.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::http::uri::Authority;
+use rand::Rng;
+
+/// Smoothing factor for the latency EWMA; higher weights recent samples more.
+const EWMA_ALPHA: f64 = 0.2;
+
+/// Latency (in milliseconds) a fresh backend is assumed to have, before any
+/// real samples are recorded.
+const INITIAL_LATENCY_MS: f64 = 50.0;
+
+/// How much a recent failure inflates a backend's effective latency.
+const FAILURE_PENALTY_MS: f64 = 2_000.0;
+
+/// How long a failure continues to count against a backend's weight.
+const FAILURE_DECAY: Duration = Duration::from_secs(30);
+
+/// Tracks one backend's recent performance: an EWMA of observed response
+/// latency plus a short-lived penalty applied after a failure.
+pub struct Backend {
+    authority: Authority,
+    ewma_latency_ms: Mutex<f64>,
+    last_failure: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    fn new(authority: Authority) -> Self {
+        Backend {
+            authority,
+            ewma_latency_ms: Mutex::new(INITIAL_LATENCY_MS),
+            last_failure: Mutex::new(None),
+        }
+    }
+
+    pub fn authority(&self) -> &Authority {
+        &self.authority
+    }
+
+    /// Record a successful request's latency.
+    pub fn record_success(&self, latency: Duration) {
+        let sample_ms = latency.as_secs_f64() * 1000.0;
+        let mut ewma = self.ewma_latency_ms.lock().unwrap();
+        *ewma = EWMA_ALPHA * sample_ms + (1.0 - EWMA_ALPHA) * *ewma;
+    }
+
+    /// Record a connection error or 5xx response, penalizing this backend
+    /// for [`FAILURE_DECAY`].
+    pub fn record_failure(&self) {
+        *self.last_failure.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Effective weight for scheduling: inversely proportional to EWMA
+    /// latency plus any active failure penalty. Higher is more preferred.
+    fn weight(&self) -> f64 {
+        let ewma = *self.ewma_latency_ms.lock().unwrap();
+        let penalty = match *self.last_failure.lock().unwrap() {
+            Some(at) if at.elapsed() < FAILURE_DECAY => {
+                let remaining = (FAILURE_DECAY - at.elapsed()).as_secs_f64();
+                FAILURE_PENALTY_MS * remaining / FAILURE_DECAY.as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        1.0 / (ewma + penalty + 1.0)
+    }
+}
+
+/// Weighted-random scheduler over a fixed set of backends.
+pub struct Scheduler {
+    backends: Vec<Arc<Backend>>,
+}
+
+impl Scheduler {
+    pub fn new(authorities: Vec<Authority>) -> Self {
+        Scheduler {
+            backends: authorities
+                .into_iter()
+                .map(|a| Arc::new(Backend::new(a)))
+                .collect(),
+        }
+    }
+
+    /// Return every backend ordered most-to-least preferred for this
+    /// request, via weighted random selection without replacement. Callers
+    /// send to the first and may try the rest in order after a failure.
+    pub fn pick_order(&self) -> Vec<Arc<Backend>> {
+        let mut remaining = self.backends.clone();
+        let mut order = Vec::with_capacity(remaining.len());
+        let mut rng = rand::thread_rng();
+
+        while !remaining.is_empty() {
+            let weights: Vec<f64> = remaining.iter().map(|backend| backend.weight()).collect();
+            let total: f64 = weights.iter().sum();
+            let mut pick = rng.gen_range(0.0..total);
+            let mut chosen = weights.len() - 1;
+            for (i, weight) in weights.iter().enumerate() {
+                if pick < *weight {
+                    chosen = i;
+                    break;
+                }
+                pick -= *weight;
+            }
+            order.push(remaining.remove(chosen));
+        }
+
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_backend_has_weight_from_initial_latency() {
+        let backend = Backend::new(Authority::from_static("example.com:80"));
+        assert_eq!(backend.weight(), 1.0 / (INITIAL_LATENCY_MS + 1.0));
+    }
+
+    #[test]
+    fn lower_latency_yields_higher_weight() {
+        let fast = Backend::new(Authority::from_static("fast.example.com:80"));
+        let slow = Backend::new(Authority::from_static("slow.example.com:80"));
+        fast.record_success(Duration::from_millis(5));
+        slow.record_success(Duration::from_millis(500));
+        assert!(fast.weight() > slow.weight());
+    }
+
+    #[test]
+    fn recent_failure_lowers_weight() {
+        let backend = Backend::new(Authority::from_static("example.com:80"));
+        let before = backend.weight();
+        backend.record_failure();
+        assert!(backend.weight() < before);
+    }
+
+    #[test]
+    fn pick_order_returns_every_backend_exactly_once() {
+        let scheduler = Scheduler::new(vec![
+            Authority::from_static("a.example.com:80"),
+            Authority::from_static("b.example.com:80"),
+            Authority::from_static("c.example.com:80"),
+        ]);
+        let order = scheduler.pick_order();
+        assert_eq!(order.len(), 3);
+        let mut authorities: Vec<&Authority> = order.iter().map(|b| b.authority()).collect();
+        authorities.sort_by_key(|a| a.to_string());
+        assert_eq!(
+            authorities,
+            vec![
+                &Authority::from_static("a.example.com:80"),
+                &Authority::from_static("b.example.com:80"),
+                &Authority::from_static("c.example.com:80"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,44 @@
+//! In-memory certificate storage and SNI-based resolution for the HTTPS listener.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+/// Holds one [`CertifiedKey`] per SNI hostname, refreshed in place as the
+/// ACME renewal task issues new certificates.
+#[derive(Clone, Default)]
+pub struct CertStore {
+    certs: Arc<RwLock<HashMap<String, Arc<CertifiedKey>>>>,
+}
+
+impl CertStore {
+    pub fn new() -> Self {
+        CertStore::default()
+    }
+
+    /// Install or replace the certificate for `host`.
+    pub fn insert(&self, host: String, key: Arc<CertifiedKey>) {
+        self.certs.write().insert(host, key);
+    }
+
+    /// Look up the current certificate for `host`, if any.
+    pub fn get(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        self.certs.read().get(host).cloned()
+    }
+
+    /// Hostnames currently covered by a stored certificate.
+    pub fn hosts(&self) -> Vec<String> {
+        self.certs.read().keys().cloned().collect()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello.server_name()?;
+        self.get(host)
+    }
+}
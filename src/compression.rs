@@ -0,0 +1,199 @@
+//! Transparent response compression, applied when the backend served an
+//! uncompressed body the client is willing to accept a codec for.
+
+use async_compression::tokio::bufread::{BrotliEncoder, DeflateEncoder, GzipEncoder};
+use futures::StreamExt;
+use hyper::{
+    header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use tokio::io::BufReader;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// MIME types eligible for compression when no allowlist is configured.
+pub const DEFAULT_MIME_TYPES: &[&str] = &["text/*", "application/json", "application/javascript"];
+
+/// Response bodies smaller than this are left alone; the framing overhead of
+/// a compressed stream isn't worth it.
+const MIN_COMPRESSIBLE_SIZE: u64 = 860;
+
+/// Compression behavior, built once from `Opt` and shared across requests.
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub mime_types: Vec<String>,
+}
+
+/// Codecs this proxy can produce, in the order they're preferred when a
+/// client's `Accept-Encoding` allows more than one.
+#[derive(Clone, Copy)]
+enum Codec {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Codec {
+    fn token(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Deflate => "deflate",
+            Codec::Brotli => "br",
+        }
+    }
+}
+
+/// Pick the best codec the client advertises support for, preferring gzip,
+/// then deflate, then brotli. A codec given `q=0` (e.g. `gzip;q=0`) is
+/// treated as explicitly rejected, per RFC 7231 section 5.3.1.
+fn pick_codec(accept_encoding: &str) -> Option<Codec> {
+    let offered: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .map(|entry| {
+            let mut parts = entry.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (name, q)
+        })
+        .collect();
+    [
+        ("gzip", Codec::Gzip),
+        ("deflate", Codec::Deflate),
+        ("br", Codec::Brotli),
+    ]
+    .into_iter()
+    .find(|(name, _)| {
+        offered
+            .iter()
+            .any(|(o, q)| o.eq_ignore_ascii_case(name) && *q > 0.0)
+    })
+    .map(|(_, codec)| codec)
+}
+
+/// Does `content_type` match an entry in `allowlist`? Entries ending in
+/// `/*` match any subtype of that top-level type.
+fn mime_allowed(content_type: &str, allowlist: &[String]) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    allowlist
+        .iter()
+        .any(|pattern| match pattern.strip_suffix("/*") {
+            Some(top_level) => mime
+                .split_once('/')
+                .map(|(mime_top, _)| mime_top.eq_ignore_ascii_case(top_level))
+                .unwrap_or(false),
+            None => mime.eq_ignore_ascii_case(pattern),
+        })
+}
+
+/// Compress `res`'s body in place if `config` allows it, the client's
+/// `Accept-Encoding` offers a supported codec, and the response looks like
+/// an uncompressed, large-enough, allowlisted payload.
+pub fn maybe_compress(
+    config: &CompressionConfig,
+    accept_encoding: Option<&HeaderValue>,
+    res: Response<Body>,
+) -> Response<Body> {
+    if !config.enabled {
+        return res;
+    }
+    if matches!(
+        res.status(),
+        StatusCode::SWITCHING_PROTOCOLS | StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+    ) {
+        return res;
+    }
+    if res.headers().contains_key(CONTENT_ENCODING) {
+        return res;
+    }
+    match res.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) if mime_allowed(content_type, &config.mime_types) => {}
+        _ => return res,
+    }
+
+    let large_enough = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|len| len >= MIN_COMPRESSIBLE_SIZE)
+        .unwrap_or(true); // no Content-Length (e.g. chunked) - compress anyway
+    if !large_enough {
+        return res;
+    }
+
+    let codec = match accept_encoding
+        .and_then(|v| v.to_str().ok())
+        .and_then(pick_codec)
+    {
+        Some(codec) => codec,
+        None => return res,
+    };
+
+    let (mut parts, body) = res.into_parts();
+    let reader = BufReader::new(StreamReader::new(body.map(|chunk| {
+        chunk.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+    })));
+    let body = match codec {
+        Codec::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Codec::Deflate => Body::wrap_stream(ReaderStream::new(DeflateEncoder::new(reader))),
+        Codec::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(codec.token()));
+    Response::from_parts(parts, body)
+}
+
+/// Take the `--compress-mime-types` entries (already split on commas by
+/// clap), falling back to [`DEFAULT_MIME_TYPES`] if there are none.
+pub fn parse_mime_types(raw: &[String]) -> Vec<String> {
+    if raw.is_empty() {
+        DEFAULT_MIME_TYPES.iter().map(|s| s.to_string()).collect()
+    } else {
+        raw.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec_token(accept_encoding: &str) -> Option<&'static str> {
+        pick_codec(accept_encoding).map(Codec::token)
+    }
+
+    #[test]
+    fn picks_preferred_codec_among_those_offered() {
+        assert_eq!(codec_token("gzip, deflate, br"), Some("gzip"));
+        assert_eq!(codec_token("deflate, br"), Some("deflate"));
+        assert_eq!(codec_token("br"), Some("br"));
+        assert_eq!(codec_token("identity"), None);
+        assert_eq!(codec_token(""), None);
+    }
+
+    #[test]
+    fn rejects_codec_explicitly_disabled_with_q_zero() {
+        assert_eq!(codec_token("gzip;q=0, deflate, br"), Some("deflate"));
+        assert_eq!(codec_token("gzip;q=0.0, br;q=0"), None);
+        assert_eq!(codec_token("gzip;q=0, identity"), None);
+    }
+
+    #[test]
+    fn accepts_codec_with_nonzero_q() {
+        assert_eq!(codec_token("gzip;q=0.5"), Some("gzip"));
+        assert_eq!(codec_token("gzip ; q=1.0"), Some("gzip"));
+    }
+
+    #[test]
+    fn mime_allowed_matches_exact_and_wildcard_entries() {
+        let allowlist = vec!["application/json".to_owned(), "text/*".to_owned()];
+        assert!(mime_allowed("application/json", &allowlist));
+        assert!(mime_allowed("application/json; charset=utf-8", &allowlist));
+        assert!(mime_allowed("text/html", &allowlist));
+        assert!(!mime_allowed("application/javascript", &allowlist));
+        assert!(!mime_allowed("image/png", &allowlist));
+    }
+}
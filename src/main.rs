@@ -1,14 +1,35 @@
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+mod acme;
+mod backend;
+mod cert_store;
+mod compression;
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::*;
 use clap::Clap;
 use hyper::{
-    header::{HeaderName, HOST},
+    header::{HeaderName, HeaderValue, ACCEPT_ENCODING, CONNECTION, HOST, UPGRADE},
     http::uri::{Authority, Parts, Scheme},
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Client, Request, Response, Server, StatusCode, Uri,
 };
+use tokio::io::copy_bidirectional;
+use tokio_rustls::TlsAcceptor;
+use unicase::Ascii;
+
+use acme::{AcmeConfig, ChallengeResponder};
+use backend::Scheduler;
+use cert_store::CertStore;
+use compression::CompressionConfig;
+
+/// Path prefix ACME `http-01` challenges arrive under.
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
 
 /// Command line options
 #[derive(Clap, Debug)]
@@ -19,15 +40,49 @@ struct Opt {
     /// Host/port to bind to
     #[clap(long, default_value = "0.0.0.0:3000")]
     bind: String,
-    /// Host to direct requests to
+    /// Host to direct requests to. Pass once to apply to every `--destport`,
+    /// or once per `--destport` to mix hosts
     #[clap(long, default_value = "127.0.0.1")]
-    desthost: String,
-    /// Port to direct requests to
+    desthost: Vec<String>,
+    /// Port of a backend to direct requests to. Pass more than once to
+    /// schedule across multiple weighted, latency-tracked backends
     #[clap(long)]
-    destport: u16,
+    destport: Vec<u16>,
     /// HTTP request header containing the new Host header
     #[clap(long, default_value = "X-Smuggle-Host")]
     smuggle_header: HeaderName,
+    /// Host/port to bind the TLS-terminating listener to. When set, certificates
+    /// are provisioned automatically via ACME for every `--acme-host`.
+    #[clap(long)]
+    https_bind: Option<String>,
+    /// Contact email registered with the ACME account
+    #[clap(long, requires = "https-bind")]
+    acme_email: Option<String>,
+    /// ACME directory URL to order certificates from
+    #[clap(long, default_value = "https://acme-v02.api.letsencrypt.org/directory")]
+    acme_directory: String,
+    /// Hostname to request an ACME certificate for. May be passed more than once.
+    #[clap(long = "acme-host")]
+    acme_hosts: Vec<String>,
+    /// Trust and extend an incoming X-Forwarded-For chain instead of overwriting
+    /// it. Only safe when this proxy is itself fronted by a trusted proxy;
+    /// otherwise clients can spoof their address.
+    #[clap(long)]
+    trust_forwarded_for: bool,
+    /// Transparently compress backend responses the client can accept
+    #[clap(long)]
+    enable_compression: bool,
+    /// Comma-separated MIME type allowlist for compression (`/*` wildcards
+    /// match any subtype)
+    #[clap(
+        long,
+        use_delimiter = true,
+        default_value = "text/*,application/json,application/javascript"
+    )]
+    compress_mime_types: Vec<String>,
+    /// Seconds to wait for a backend to respond before returning 504
+    #[clap(long, default_value = "60")]
+    proxy_timeout: u64,
 }
 
 impl Opt {
@@ -42,26 +97,70 @@ impl Opt {
     }
 }
 
+/// Pair up `--desthost`/`--destport` into backend authorities. A single
+/// `--desthost` is broadcast across every `--destport`; otherwise the two
+/// lists must be given the same number of times.
+fn backend_authorities(opt: &Opt) -> Result<Vec<Authority>> {
+    ensure!(
+        !opt.destport.is_empty(),
+        "At least one --destport is required"
+    );
+    let hosts: Vec<&String> = if opt.desthost.len() == 1 {
+        std::iter::repeat(&opt.desthost[0])
+            .take(opt.destport.len())
+            .collect()
+    } else {
+        ensure!(
+            opt.desthost.len() == opt.destport.len(),
+            "--desthost must be given once (to apply to every --destport) or the same number of times as --destport"
+        );
+        opt.desthost.iter().collect()
+    };
+    hosts
+        .into_iter()
+        .zip(opt.destport.iter())
+        .map(|(host, port)| {
+            format!("{}:{}", host, port)
+                .parse()
+                .context("Unable to parse backend Authority")
+        })
+        .collect()
+}
+
 /// State of the application
 struct App {
     /// Outgoing HTTP(S) connections
     client: Client<hyper::client::HttpConnector>,
     /// HTTP request header containing the new Host header
     smuggle_header: HeaderName,
-    /// Destination
-    authority: Authority,
+    /// Backends to schedule requests across
+    scheduler: Scheduler,
+    /// Pending ACME `http-01` challenges, answered on the plaintext listener
+    challenges: ChallengeResponder,
+    /// Whether to trust and extend an incoming X-Forwarded-For chain rather
+    /// than overwriting it with just the immediate peer address
+    trust_forwarded_for: bool,
+    /// Response compression behavior
+    compression: CompressionConfig,
+    /// How long to wait for a backend to respond before returning 504
+    proxy_timeout: Duration,
 }
 
 impl App {
-    fn new(opt: Opt) -> Result<Self> {
+    fn new(opt: &Opt, challenges: ChallengeResponder) -> Result<Self> {
         let client = Client::new();
-        let authority = format!("{}:{}", opt.desthost, opt.destport)
-            .parse()
-            .context("Unable to parse Authority")?;
+        let scheduler = Scheduler::new(backend_authorities(opt)?);
         Ok(App {
             client,
-            smuggle_header: opt.smuggle_header,
-            authority,
+            smuggle_header: opt.smuggle_header.clone(),
+            scheduler,
+            challenges,
+            trust_forwarded_for: opt.trust_forwarded_for,
+            compression: CompressionConfig {
+                enabled: opt.enable_compression,
+                mime_types: compression::parse_mime_types(&opt.compress_mime_types),
+            },
+            proxy_timeout: Duration::from_secs(opt.proxy_timeout),
         })
     }
 
@@ -69,13 +168,25 @@ impl App {
         self: Arc<Self>,
         uuid: uuid::Uuid,
         conn: SocketAddr,
+        scheme: Scheme,
         mut req: Request<Body>,
     ) -> Result<Response<Body>> {
         log::debug!("{}: Incoming request from {}: {:?}", uuid, conn, req);
-        for header in HOP_BY_HOPS {
-            req.headers_mut().remove(*header);
+
+        if let Some(token) = req.uri().path().strip_prefix(ACME_CHALLENGE_PREFIX) {
+            return Ok(match self.challenges.respond(token) {
+                Some(key_authorization) => Response::new(Body::from(key_authorization)),
+                None => {
+                    let mut res = Response::new(Body::from("Unknown ACME challenge token"));
+                    *res.status_mut() = StatusCode::NOT_FOUND;
+                    res
+                }
+            });
         }
 
+        let upgrade_type = requested_upgrade(&req);
+        strip_hop_by_hop(req.headers_mut(), upgrade_type.is_some());
+
         let host = req
             .headers_mut()
             .remove(&self.smuggle_header)
@@ -85,41 +196,343 @@ impl App {
                     self.smuggle_header
                 )
             })?;
+        apply_forwarding_headers(
+            req.headers_mut(),
+            conn,
+            &scheme,
+            &host,
+            self.trust_forwarded_for,
+        );
         req.headers_mut().insert(HOST, host);
 
-        let mut parts = Parts::default();
-        parts.scheme = Some(Scheme::HTTP);
-        parts.authority = Some(self.authority.clone());
-        parts.path_and_query = req.uri_mut().path_and_query().cloned();
-        *req.uri_mut() = Uri::from_parts(parts).context("Unable to construct destination URI")?;
-        self.client
-            .request(req)
-            .await
-            .context("Error performing reverse proxied request")
+        // Only bodyless, idempotent requests are safe to rebuild against a
+        // different backend if the first pick fails.
+        let retryable = upgrade_type.is_none()
+            && matches!(*req.method(), hyper::Method::GET | hyper::Method::HEAD);
+
+        let mut dest_parts = Parts::default();
+        dest_parts.scheme = Some(Scheme::HTTP);
+        dest_parts.path_and_query = req.uri_mut().path_and_query().cloned();
+
+        let mut backends = self.scheduler.pick_order().into_iter();
+        let mut backend = backends
+            .next()
+            .context("No backend configured (--destport was never given)")?;
+
+        let mut uri_parts = clone_parts(&dest_parts);
+        uri_parts.authority = Some(backend.authority().clone());
+        *req.uri_mut() =
+            Uri::from_parts(uri_parts).context("Unable to construct destination URI")?;
+
+        let req_upgrade = upgrade_type.as_ref().map(|_| hyper::upgrade::on(&mut req));
+        let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+        let method = req.method().clone();
+        let headers_template = req.headers().clone();
+
+        let mut next_req = Some(req);
+        let outcome = loop {
+            let attempt = next_req
+                .take()
+                .expect("a request is always queued for the current backend attempt");
+            let start = Instant::now();
+            let attempt_result =
+                tokio::time::timeout(self.proxy_timeout, self.client.request(attempt)).await;
+
+            let (failed, this_outcome) = match attempt_result {
+                Result::Ok(Result::Ok(res)) if res.status().is_server_error() => {
+                    (true, BackendOutcome::Response(res))
+                }
+                Result::Ok(Result::Ok(res)) => (false, BackendOutcome::Response(res)),
+                Result::Ok(Result::Err(err)) if err.is_connect() => {
+                    (true, BackendOutcome::Unreachable(err.into()))
+                }
+                Result::Ok(Result::Err(err)) => (true, BackendOutcome::Other(err.into())),
+                Result::Err(_elapsed) => (true, BackendOutcome::Timeout),
+            };
+            if failed {
+                backend.record_failure();
+            } else {
+                backend.record_success(start.elapsed());
+            }
+
+            if !failed || !retryable {
+                break this_outcome;
+            }
+            backend = match backends.next() {
+                Some(next_backend) => next_backend,
+                None => break this_outcome,
+            };
+
+            let mut uri_parts = clone_parts(&dest_parts);
+            uri_parts.authority = Some(backend.authority().clone());
+            let uri = Uri::from_parts(uri_parts).context("Unable to construct destination URI")?;
+            let mut retry_req = Request::builder()
+                .method(method.clone())
+                .uri(uri)
+                .body(Body::empty())
+                .context("Unable to build retry request")?;
+            *retry_req.headers_mut() = headers_template.clone();
+            next_req = Some(retry_req);
+        };
+
+        let mut res = match backend_outcome_response(
+            outcome,
+            uuid,
+            backend.authority(),
+            self.proxy_timeout,
+        )? {
+            OutcomeResponse::Continue(res) => res,
+            OutcomeResponse::Terminal(res) => return Ok(res),
+        };
+
+        let switching_protocols =
+            upgrade_type.is_some() && res.status() == StatusCode::SWITCHING_PROTOCOLS;
+        strip_hop_by_hop(res.headers_mut(), switching_protocols);
+
+        if let (Some(expected), Some(req_upgrade)) = (upgrade_type, req_upgrade) {
+            if switching_protocols {
+                let actual = res.headers().get(UPGRADE).cloned();
+                check_upgrade_match(&expected, actual.as_ref())?;
+                res.headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+                res.headers_mut().insert(UPGRADE, expected);
+
+                let res_upgrade = hyper::upgrade::on(&mut res);
+                tokio::spawn(async move {
+                    match tokio::try_join!(req_upgrade, res_upgrade) {
+                        Result::Ok((mut client, mut backend)) => {
+                            if let Err(err) = copy_bidirectional(&mut client, &mut backend).await {
+                                log::warn!("Error tunneling upgraded connection: {:?}", err);
+                            }
+                        }
+                        Result::Err(err) => {
+                            log::warn!("Error obtaining upgraded connection: {:?}", err)
+                        }
+                    }
+                });
+            }
+        }
+
+        Ok(compression::maybe_compress(
+            &self.compression,
+            accept_encoding.as_ref(),
+            res,
+        ))
     }
 }
 
+/// Result of a single attempt against a backend.
+enum BackendOutcome {
+    /// The backend responded (possibly with a 5xx status).
+    Response(Response<Body>),
+    /// The backend did not respond within `--proxy-timeout`.
+    Timeout,
+    /// The connection to the backend could not be established.
+    Unreachable(anyhow::Error),
+    /// Some other I/O or protocol error occurred talking to the backend.
+    Other(anyhow::Error),
+}
+
+/// What to do with the response produced from a [`BackendOutcome`]: either
+/// it's a real backend response that still needs upgrade/compression
+/// handling, or it's a synthetic error response that should go straight to
+/// the client.
+enum OutcomeResponse {
+    Continue(Response<Body>),
+    Terminal(Response<Body>),
+}
+
+/// Turn the outcome of the final attempt against a backend into a response,
+/// logging and mapping timeouts to 504 and connect failures to 502. Any
+/// other backend error is bubbled up to the catch-all 500 handler.
+fn backend_outcome_response(
+    outcome: BackendOutcome,
+    uuid: uuid::Uuid,
+    backend: &Authority,
+    proxy_timeout: Duration,
+) -> Result<OutcomeResponse> {
+    match outcome {
+        BackendOutcome::Response(res) => Ok(OutcomeResponse::Continue(res)),
+        BackendOutcome::Timeout => {
+            log::warn!(
+                "{}: backend at {} timed out after {:?}",
+                uuid,
+                backend,
+                proxy_timeout
+            );
+            let mut res = Response::new(Body::from(format!(
+                "Backend did not respond in time, error identifier {}",
+                uuid
+            )));
+            *res.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+            Ok(OutcomeResponse::Terminal(res))
+        }
+        BackendOutcome::Unreachable(err) => {
+            log::warn!("{}: backend at {} unreachable: {:?}", uuid, backend, err);
+            let mut res = Response::new(Body::from(format!(
+                "Backend unreachable, error identifier {}",
+                uuid
+            )));
+            *res.status_mut() = StatusCode::BAD_GATEWAY;
+            Ok(OutcomeResponse::Terminal(res))
+        }
+        BackendOutcome::Other(err) => Err(err).context("Error performing reverse proxied request"),
+    }
+}
+
+/// `hyper::http::uri::Parts` doesn't implement `Clone` even though every
+/// field it has does, so rebuild one field-by-field instead.
+fn clone_parts(parts: &Parts) -> Parts {
+    let mut clone = Parts::default();
+    clone.scheme = parts.scheme.clone();
+    clone.authority = parts.authority.clone();
+    clone.path_and_query = parts.path_and_query.clone();
+    clone
+}
+
+/// If `req` is requesting a protocol upgrade (a `Connection` header
+/// containing the `upgrade` token), return the requested `Upgrade` value.
+fn requested_upgrade(req: &Request<Body>) -> Option<HeaderValue> {
+    let wants_upgrade = req
+        .headers()
+        .get(CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        })
+        .unwrap_or(false);
+    wants_upgrade
+        .then(|| req.headers().get(UPGRADE).cloned())
+        .flatten()
+}
+
+/// Verify the backend echoed back the exact `Upgrade` token the client
+/// requested. A backend that switches protocols but disagrees on which one
+/// is misbehaving and its connection shouldn't be tunneled.
+fn check_upgrade_match(expected: &HeaderValue, actual: Option<&HeaderValue>) -> Result<()> {
+    ensure!(
+        actual == Some(expected),
+        "Backend replied with mismatched Upgrade type: requested {:?}, got {:?}",
+        expected,
+        actual
+    );
+    Ok(())
+}
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Record the real client IP, the scheme the request arrived over, and the
+/// smuggled host in the `X-Forwarded-*` headers the backend sees.
+///
+/// When `trust_upstream` is false (the default), an existing
+/// `X-Forwarded-For` is overwritten with just `conn`'s address, since an
+/// untrusted client could otherwise forge the chain. When true, `conn` is
+/// appended to whatever chain is already present, for deployments where
+/// this proxy sits behind another trusted proxy.
+fn apply_forwarding_headers(
+    headers: &mut hyper::HeaderMap,
+    conn: SocketAddr,
+    scheme: &Scheme,
+    host: &HeaderValue,
+    trust_upstream: bool,
+) {
+    let client_ip = conn.ip().to_string();
+    let forwarded_for = if trust_upstream {
+        match headers.get(&X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+            Some(existing) => format!("{}, {}", existing, client_ip),
+            None => client_ip,
+        }
+    } else {
+        client_ip
+    };
+    if let Result::Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+
+    let proto = if *scheme == Scheme::HTTPS {
+        "https"
+    } else {
+        "http"
+    };
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+
+    headers.insert(X_FORWARDED_HOST, host.clone());
+}
+
 /// Hop by hop headers that should not be forwarded
 ///
-/// See https://www.freesoft.org/CIE/RFC/2068/143.htm
+/// See https://www.rfc-editor.org/rfc/rfc7230#section-6.1
 const HOP_BY_HOPS: &[&str] = &[
     "Connection",
-    "Keep-alive",
+    "Keep-Alive",
     "Public",
     "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
     "Transfer-Encoding",
     "Upgrade",
 ];
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opt = Opt::parse();
-    opt.init_logger();
-    log::debug!("opt: {:?}", opt);
+/// Strip hop-by-hop headers from `headers` per RFC 7230 section 6.1: every
+/// header the `Connection` header names, plus the canonical [`HOP_BY_HOPS`]
+/// set, matched case-insensitively. When `keep_upgrade` is set (an upgrade
+/// is in flight), `Connection`/`Upgrade` themselves are left alone so they
+/// can be forwarded or re-attached by the caller.
+fn strip_hop_by_hop(headers: &mut hyper::HeaderMap, keep_upgrade: bool) {
+    let named: Vec<Ascii<String>> = headers
+        .get(CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .map(|token| Ascii::new(token.trim().to_owned()))
+                .collect()
+        })
+        .unwrap_or_default();
 
-    let addr: SocketAddr = opt.bind.parse().context("Cannot parse as bind host/port")?;
-    let app = Arc::new(App::new(opt)?);
+    let present: Vec<HeaderName> = headers.keys().cloned().collect();
+    for name in present {
+        if keep_upgrade && (name == CONNECTION || name == UPGRADE) {
+            continue;
+        }
+        let ascii_name = Ascii::new(name.as_str().to_owned());
+        let is_named = named.contains(&ascii_name);
+        let is_canonical = HOP_BY_HOPS
+            .iter()
+            .any(|candidate| ascii_name == Ascii::new((*candidate).to_owned()));
+        if is_named || is_canonical {
+            headers.remove(&name);
+        }
+    }
+}
+
+/// Wrap a handled request in the catch-all 500 response used by both the
+/// plaintext and TLS listeners.
+async fn serve_one(
+    app: Arc<App>,
+    uuid: uuid::Uuid,
+    conn: SocketAddr,
+    scheme: Scheme,
+    req: Request<Body>,
+) -> Response<Body> {
+    app.handle_request(uuid, conn, scheme, req)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("Unhandled error occurred. uuid=={}: {:?}", uuid, err);
+            let mut res = Response::new(
+                format!("An unhandled error occurred, error identifier {}", uuid).into(),
+            );
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            res
+        })
+}
 
+/// Run the plaintext listener, also responsible for answering ACME
+/// `http-01` challenges for the HTTPS listener's certificates.
+async fn run_http(addr: SocketAddr, app: Arc<App>) -> Result<()> {
     let make_svc = make_service_fn(move |conn: &AddrStream| {
         let app = app.clone();
         let conn = conn.remote_addr();
@@ -128,28 +541,344 @@ async fn main() -> Result<()> {
                 let app = app.clone();
                 async move {
                     let uuid = uuid::Uuid::new_v4();
-                    let res = app
-                        .clone()
-                        .handle_request(uuid, conn, req)
-                        .await
-                        .unwrap_or_else(|err| {
-                            log::error!("Unhandled error occurred. uuid=={}: {:?}", uuid, err);
-                            let mut res = Response::new(
-                                format!("An unhandled error occurred, error identifier {}", uuid)
-                                    .into(),
-                            );
-                            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                            res
-                        });
-                    Ok::<_, Infallible>(res)
+                    Ok::<_, Infallible>(serve_one(app, uuid, conn, Scheme::HTTP, req).await)
                 }
             }))
         }
     });
 
-    let server = Server::bind(&addr).serve(make_svc);
-
-    server
+    Server::bind(&addr)
+        .serve(make_svc)
         .await
         .context("Hyper server exited, which should not happen")
 }
+
+/// Run the TLS-terminating listener, resolving certificates by SNI out of
+/// `cert_store`.
+async fn run_https(addr: SocketAddr, app: Arc<App>, cert_store: CertStore) -> Result<()> {
+    let tls_config = Arc::new(
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(cert_store)),
+    );
+    let acceptor = TlsAcceptor::from(tls_config);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .context("Unable to bind HTTPS listener")?;
+
+    loop {
+        let (stream, conn) = listener
+            .accept()
+            .await
+            .context("Unable to accept HTTPS connection")?;
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Result::Ok(stream) => stream,
+                Result::Err(err) => {
+                    log::warn!("TLS handshake with {} failed: {:?}", conn, err);
+                    return;
+                }
+            };
+            let service = service_fn(move |req| {
+                let app = app.clone();
+                async move {
+                    let uuid = uuid::Uuid::new_v4();
+                    Ok::<_, Infallible>(serve_one(app, uuid, conn, Scheme::HTTPS, req).await)
+                }
+            });
+            if let Err(err) = hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+            {
+                log::warn!("Error serving HTTPS connection from {}: {:?}", conn, err);
+            }
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opt = Opt::parse();
+    opt.init_logger();
+    log::debug!("opt: {:?}", opt);
+
+    let addr: SocketAddr = opt.bind.parse().context("Cannot parse as bind host/port")?;
+    let challenges = ChallengeResponder::new();
+    let app = Arc::new(App::new(&opt, challenges.clone())?);
+
+    // Spawned (not just constructed) so the plaintext listener is already
+    // accepting connections below, where ACME provisioning needs it to
+    // answer the CA's http-01 validation requests.
+    let http = tokio::spawn(run_http(addr, app.clone()));
+
+    match &opt.https_bind {
+        None => http.await.context("HTTP listener task panicked")?,
+        Some(https_bind) => {
+            let https_addr: SocketAddr = https_bind
+                .parse()
+                .context("Cannot parse --https-bind as host/port")?;
+            let email = opt
+                .acme_email
+                .clone()
+                .context("--acme-email is required when --https-bind is set")?;
+            let cert_store = CertStore::new();
+            let acme_config = Arc::new(AcmeConfig {
+                email,
+                directory: opt.acme_directory.clone(),
+                hosts: opt.acme_hosts.clone(),
+            });
+
+            acme::provision(acme_config.clone(), cert_store.clone(), challenges.clone()).await?;
+            acme::spawn_renewal_task(acme_config, cert_store.clone(), challenges);
+
+            let https = run_https(https_addr, app, cert_store);
+            let http = async { http.await.context("HTTP listener task panicked")? };
+            tokio::try_join!(http, https)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> hyper::HeaderMap {
+        let mut headers = hyper::HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn strips_canonical_hop_by_hop_headers() {
+        let mut h = headers(&[
+            ("Connection", "keep-alive"),
+            ("Keep-Alive", "timeout=5"),
+            ("Public", "GET, HEAD"),
+            ("Content-Type", "text/plain"),
+        ]);
+        strip_hop_by_hop(&mut h, false);
+        assert!(!h.contains_key("Keep-Alive"));
+        assert!(!h.contains_key("Public"));
+        assert!(h.contains_key("Content-Type"));
+    }
+
+    #[test]
+    fn strips_headers_named_by_connection() {
+        let mut h = headers(&[
+            ("Connection", "X-Custom-Hop"),
+            ("X-Custom-Hop", "should be removed"),
+            ("X-Keep", "should stay"),
+        ]);
+        strip_hop_by_hop(&mut h, false);
+        assert!(!h.contains_key("X-Custom-Hop"));
+        assert!(h.contains_key("X-Keep"));
+    }
+
+    #[test]
+    fn keeps_connection_and_upgrade_when_upgrading() {
+        let mut h = headers(&[
+            ("Connection", "Upgrade"),
+            ("Upgrade", "websocket"),
+            ("Keep-Alive", "timeout=5"),
+        ]);
+        strip_hop_by_hop(&mut h, true);
+        assert!(h.contains_key("Connection"));
+        assert!(h.contains_key("Upgrade"));
+        assert!(!h.contains_key("Keep-Alive"));
+    }
+
+    #[test]
+    fn apply_forwarding_headers_overwrites_by_default() {
+        let mut h = headers(&[("X-Forwarded-For", "203.0.113.1")]);
+        let conn: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let host = HeaderValue::from_static("example.com");
+        apply_forwarding_headers(&mut h, conn, &Scheme::HTTP, &host, false);
+        assert_eq!(h.get(&X_FORWARDED_FOR).unwrap(), "127.0.0.1");
+        assert_eq!(h.get(&X_FORWARDED_PROTO).unwrap(), "http");
+        assert_eq!(h.get(&X_FORWARDED_HOST).unwrap(), "example.com");
+    }
+
+    #[test]
+    fn apply_forwarding_headers_extends_when_trusted() {
+        let mut h = headers(&[("X-Forwarded-For", "203.0.113.1")]);
+        let conn: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let host = HeaderValue::from_static("example.com");
+        apply_forwarding_headers(&mut h, conn, &Scheme::HTTPS, &host, true);
+        assert_eq!(h.get(&X_FORWARDED_FOR).unwrap(), "203.0.113.1, 127.0.0.1");
+        assert_eq!(h.get(&X_FORWARDED_PROTO).unwrap(), "https");
+    }
+
+    fn opt_with(desthost: Vec<&str>, destport: Vec<u16>) -> Opt {
+        Opt {
+            verbose: false,
+            bind: "0.0.0.0:3000".to_owned(),
+            desthost: desthost.into_iter().map(str::to_owned).collect(),
+            destport,
+            smuggle_header: HeaderName::from_static("x-smuggle-host"),
+            https_bind: None,
+            acme_email: None,
+            acme_directory: String::new(),
+            acme_hosts: vec![],
+            trust_forwarded_for: false,
+            enable_compression: false,
+            compress_mime_types: vec![],
+            proxy_timeout: 60,
+        }
+    }
+
+    #[test]
+    fn backend_authorities_broadcasts_single_desthost() {
+        let opt = opt_with(vec!["example.com"], vec![8080, 8081]);
+        let authorities = backend_authorities(&opt).unwrap();
+        assert_eq!(
+            authorities,
+            vec![
+                Authority::from_static("example.com:8080"),
+                Authority::from_static("example.com:8081"),
+            ]
+        );
+    }
+
+    #[test]
+    fn backend_authorities_pairs_desthost_and_destport_positionally() {
+        let opt = opt_with(vec!["a.example.com", "b.example.com"], vec![8080, 8081]);
+        let authorities = backend_authorities(&opt).unwrap();
+        assert_eq!(
+            authorities,
+            vec![
+                Authority::from_static("a.example.com:8080"),
+                Authority::from_static("b.example.com:8081"),
+            ]
+        );
+    }
+
+    #[test]
+    fn backend_authorities_requires_matching_desthost_count() {
+        let opt = opt_with(vec!["a.example.com", "b.example.com"], vec![8080]);
+        assert!(backend_authorities(&opt).is_err());
+    }
+
+    #[test]
+    fn backend_authorities_requires_at_least_one_destport() {
+        let opt = opt_with(vec!["example.com"], vec![]);
+        assert!(backend_authorities(&opt).is_err());
+    }
+
+    #[test]
+    fn requested_upgrade_reads_upgrade_header_when_connection_requests_it() {
+        let req = Request::builder()
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            requested_upgrade(&req),
+            Some(HeaderValue::from_static("websocket"))
+        );
+    }
+
+    #[test]
+    fn requested_upgrade_ignores_missing_upgrade_header() {
+        let req = Request::builder()
+            .header(CONNECTION, "upgrade")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(requested_upgrade(&req), None);
+    }
+
+    #[test]
+    fn requested_upgrade_ignores_missing_connection_header() {
+        let req = Request::builder()
+            .header(UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(requested_upgrade(&req), None);
+    }
+
+    #[test]
+    fn check_upgrade_match_accepts_matching_token() {
+        let expected = HeaderValue::from_static("websocket");
+        assert!(check_upgrade_match(&expected, Some(&expected)).is_ok());
+    }
+
+    #[test]
+    fn check_upgrade_match_rejects_mismatched_or_missing_token() {
+        let expected = HeaderValue::from_static("websocket");
+        let other = HeaderValue::from_static("h2c");
+        assert!(check_upgrade_match(&expected, Some(&other)).is_err());
+        assert!(check_upgrade_match(&expected, None).is_err());
+    }
+
+    fn backend_authority() -> Authority {
+        Authority::from_static("backend.example.com:80")
+    }
+
+    #[test]
+    fn backend_outcome_response_maps_timeout_to_504() {
+        let res = backend_outcome_response(
+            BackendOutcome::Timeout,
+            uuid::Uuid::nil(),
+            &backend_authority(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        match res {
+            OutcomeResponse::Terminal(res) => {
+                assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT)
+            }
+            OutcomeResponse::Continue(_) => panic!("expected a terminal response"),
+        }
+    }
+
+    #[test]
+    fn backend_outcome_response_maps_unreachable_to_502() {
+        let res = backend_outcome_response(
+            BackendOutcome::Unreachable(anyhow!("simulated connect failure")),
+            uuid::Uuid::nil(),
+            &backend_authority(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        match res {
+            OutcomeResponse::Terminal(res) => assert_eq!(res.status(), StatusCode::BAD_GATEWAY),
+            OutcomeResponse::Continue(_) => panic!("expected a terminal response"),
+        }
+    }
+
+    #[test]
+    fn backend_outcome_response_bubbles_other_errors() {
+        let result = backend_outcome_response(
+            BackendOutcome::Other(anyhow!("simulated protocol error")),
+            uuid::Uuid::nil(),
+            &backend_authority(),
+            Duration::from_secs(60),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn backend_outcome_response_passes_through_backend_response() {
+        let mut backend_res = Response::new(Body::empty());
+        *backend_res.status_mut() = StatusCode::OK;
+        let res = backend_outcome_response(
+            BackendOutcome::Response(backend_res),
+            uuid::Uuid::nil(),
+            &backend_authority(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        match res {
+            OutcomeResponse::Continue(res) => assert_eq!(res.status(), StatusCode::OK),
+            OutcomeResponse::Terminal(_) => panic!("expected a continuable response"),
+        }
+    }
+}